@@ -1,11 +1,13 @@
 use crate::error::{with_error_path, ErrorWithPath};
 use crate::statfile::{
-    make_line, parse_line, parse_stat_file, read_stat_file, write_stat_file, STATFILE,
+    list_xattrs, make_line, parse_line, parse_stat_file, read_stat_file, write_stat_file,
+    write_stat_file_binary, Difference, STATFILE,
 };
 use clap::builder::ValueParser;
 use clap::{arg, command, ArgMatches, Command};
 use std::collections::btree_map::Entry;
-use std::fs::{metadata, symlink_metadata};
+use std::ffi::OsStr;
+use std::fs::{metadata, read_dir, symlink_metadata, Metadata};
 use std::io::{self, ErrorKind};
 use std::os::unix::ffi::OsStrExt;
 use std::process::ExitCode;
@@ -19,12 +21,22 @@ fn cmd() -> Command {
             arg!(file: <FILE> ...).value_parser(ValueParser::os_string()),
             arg!(--"follow").overrides_with("no-follow"),
             arg!(--"no-follow").overrides_with("follow"),
+            arg!(--"no-owner"),
+            arg!(-r --recursive),
+            arg!(--exclude <GLOB> ...).value_parser(ValueParser::os_string()),
+            arg!(--format <FORMAT>).value_parser(["text", "binary"]),
             arg!(-f --force),
         ]),
         Command::new("apply").args([
             arg!(file: [FILE] ...).value_parser(ValueParser::os_string()),
             arg!(--"follow").overrides_with("no-follow"),
             arg!(--"no-follow").overrides_with("follow"),
+            arg!(--"no-owner"),
+        ]),
+        Command::new("check").args([
+            arg!(file: [FILE] ...).value_parser(ValueParser::os_string()),
+            arg!(--"follow").overrides_with("no-follow"),
+            arg!(--"no-follow").overrides_with("follow"),
         ]),
     ])
 }
@@ -38,6 +50,7 @@ fn main() -> ExitCode {
     let result = match cmd().get_matches().subcommand() {
         Some(("add", matches)) => add(matches),
         Some(("apply", matches)) => apply(matches),
+        Some(("check", matches)) => check(matches),
         _ => unreachable!(),
     };
 
@@ -52,24 +65,46 @@ fn main() -> ExitCode {
 
 fn add(matches: &ArgMatches) -> Result<ExitCode, ErrorWithPath<io::Error>> {
     let follow = !matches.get_flag("no-follow");
+    let owner = !matches.get_flag("no-owner");
     let force = matches.get_flag("force");
+    let recursive = matches.get_flag("recursive");
+    let binary = matches
+        .get_one::<String>("format")
+        .is_some_and(|format| format == "binary");
+    let excludes: Vec<&[u8]> = matches
+        .get_raw("exclude")
+        .map(|values| values.map(|glob| glob.as_bytes()).collect())
+        .unwrap_or_default();
 
     let stat_file = with_error_path(STATFILE, || read_stat_file(STATFILE, true))?;
     let mut stat_file = with_error_path(STATFILE, || parse_stat_file(&stat_file))?;
 
-    for name in matches.get_raw("file").unwrap() {
-        let metadata = with_error_path(name.as_bytes(), || {
-            if follow {
-                metadata(name)
-            } else {
-                symlink_metadata(name)
-            }
-        })?;
+    let tmp = STATFILE.to_owned() + ".tmp";
 
-        let name = name.as_bytes();
-        let line = make_line(name, &metadata);
+    // Directory that will hold the stat file; writing `.filestat`/`.filestat.tmp`
+    // bumps its mtime, so we never record an mtime for it (see below).
+    let statfile_dir: &[u8] = match STATFILE.as_bytes().iter().rposition(|&b| b == b'/') {
+        Some(slash) => &STATFILE.as_bytes()[..slash],
+        None => b".",
+    };
 
-        match stat_file.entry(name.into()) {
+    let mut result = ExitCode::SUCCESS;
+
+    // Merge a single entry into the map, honouring the `--force` overwrite
+    // semantics shared with the non-recursive path. A failing `list_xattrs`
+    // (e.g. EPERM on a restricted attribute) is reported per-file and the rest
+    // of the entry is still recorded, rather than aborting the whole run.
+    let mut record = |name: &[u8], metadata: &Metadata, follow: bool, mtime: bool| -> Result<(), ErrorWithPath<io::Error>> {
+        let xattrs = match with_error_path(name, || list_xattrs(name, follow)) {
+            Ok(xattrs) => xattrs,
+            Err(err) => {
+                eprintln!("{}", err);
+                result = ExitCode::FAILURE;
+                Vec::new()
+            }
+        };
+        let line = make_line(name, metadata, owner, mtime, &xattrs);
+        match stat_file.entry(name.to_vec().into()) {
             Entry::Vacant(entry) => {
                 entry.insert(line.into());
             }
@@ -79,14 +114,119 @@ fn add(matches: &ArgMatches) -> Result<ExitCode, ErrorWithPath<io::Error>> {
                 }
             }
         }
+        Ok(())
+    };
+
+    if recursive {
+        // Stack-based walk without following symlinks, so cycles cannot trap us.
+        let mut stack: Vec<Vec<u8>> = matches
+            .get_raw("file")
+            .unwrap()
+            .map(|name| name.as_bytes().to_vec())
+            .collect();
+
+        while let Some(path) = stack.pop() {
+            if is_skipped(&path, tmp.as_bytes(), &excludes) {
+                continue;
+            }
+
+            let os_path = OsStr::from_bytes(&path);
+            let metadata = with_error_path(&path[..], || symlink_metadata(os_path))?;
+            let mtime = !(metadata.file_type().is_dir() && strip_dot_slash(&path) == statfile_dir);
+            record(&path, &metadata, false, mtime)?;
+
+            if metadata.file_type().is_dir() {
+                for entry in with_error_path(&path[..], || read_dir(os_path))? {
+                    let entry = with_error_path(&path[..], || entry)?;
+                    stack.push(join(&path, entry.file_name().as_bytes()));
+                }
+            }
+        }
+    } else {
+        for name in matches.get_raw("file").unwrap() {
+            let metadata = with_error_path(name.as_bytes(), || {
+                if follow {
+                    metadata(name)
+                } else {
+                    symlink_metadata(name)
+                }
+            })?;
+            let name = name.as_bytes();
+            let mtime = !(metadata.file_type().is_dir() && strip_dot_slash(name) == statfile_dir);
+            record(name, &metadata, follow, mtime)?;
+        }
     }
 
-    with_error_path(STATFILE, || write_stat_file(STATFILE, &stat_file))?;
-    Ok(ExitCode::SUCCESS)
+    with_error_path(STATFILE, || {
+        if binary {
+            write_stat_file_binary(STATFILE, &stat_file)
+        } else {
+            write_stat_file(STATFILE, &stat_file)
+        }
+    })?;
+    Ok(result)
+}
+
+/// Join a directory path and an entry name with a single `/` separator.
+fn join(dir: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut path = Vec::with_capacity(dir.len() + 1 + name.len());
+    path.extend_from_slice(dir);
+    if !path.is_empty() && path.last() != Some(&b'/') {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path
+}
+
+/// Strip a single leading `./` so excludes and the stat-file directory check
+/// work on paths as the user thinks of them, not as the walk spells them.
+fn strip_dot_slash(path: &[u8]) -> &[u8] {
+    path.strip_prefix(b"./".as_slice()).unwrap_or(path)
+}
+
+/// Decide whether a walked `path` should be skipped during `add -r`: the stat
+/// file itself, its temp file, or anything matching an `--exclude` glob. The
+/// comparison is against the path relative to the current directory, so a
+/// `./`-prefixed walk entry still matches the bare `.filestat` name.
+fn is_skipped(path: &[u8], tmp: &[u8], excludes: &[&[u8]]) -> bool {
+    let relative = strip_dot_slash(path);
+    relative == STATFILE.as_bytes()
+        || relative == tmp
+        || excludes.iter().any(|&glob| glob_match(glob, relative))
+}
+
+/// Match `text` against a shell-style `pattern` supporting `*` and `?`. The
+/// match is against the whole path relative to the current directory (with any
+/// leading `./` removed), and `*` spans `/`, so `--exclude 'target*'` omits
+/// both `target` and everything under it.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let (mut star, mut mark) = (None, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some(p);
+            mark = t;
+            p += 1;
+        } else if let Some(star) = star {
+            p = star + 1;
+            mark += 1;
+            t = mark;
+        } else {
+            return false;
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
 }
 
 fn apply(matches: &ArgMatches) -> Result<ExitCode, ErrorWithPath<io::Error>> {
     let follow = !matches.get_flag("no-follow");
+    let owner = !matches.get_flag("no-owner");
     let files = matches
         .get_raw("file")
         .map(|values| values.map(|name| name.as_bytes()));
@@ -103,15 +243,17 @@ fn apply(matches: &ArgMatches) -> Result<ExitCode, ErrorWithPath<io::Error>> {
     match files {
         None => {
             for (name, line) in stat_file {
-                with_error_path(name.as_ref(), || parse_line(&line)?.apply(&name, follow))
-                    .unwrap_or_else(&mut error);
+                with_error_path(name.as_ref(), || {
+                    parse_line(&line)?.apply(&name, follow, owner)
+                })
+                .unwrap_or_else(&mut error);
             }
         }
         Some(files) => {
             for name in files {
                 with_error_path(name, || {
                     if let Some(line) = stat_file.get(name) {
-                        parse_line(line)?.apply(name, follow)
+                        parse_line(line)?.apply(name, follow, owner)
                     } else {
                         Err(io::Error::new(
                             ErrorKind::InvalidInput,
@@ -126,3 +268,119 @@ fn apply(matches: &ArgMatches) -> Result<ExitCode, ErrorWithPath<io::Error>> {
 
     Ok(result)
 }
+
+fn check(matches: &ArgMatches) -> Result<ExitCode, ErrorWithPath<io::Error>> {
+    let follow = !matches.get_flag("no-follow");
+    let files = matches
+        .get_raw("file")
+        .map(|values| values.map(|name| name.as_bytes()));
+
+    let stat_file = with_error_path(STATFILE, || read_stat_file(STATFILE, false))?;
+    let stat_file = with_error_path(STATFILE, || parse_stat_file(&stat_file))?;
+
+    let report = |name: &[u8], line: &[u8]| -> Result<bool, io::Error> {
+        let os_name = OsStr::from_bytes(name);
+        let result = if follow {
+            metadata(os_name)
+        } else {
+            symlink_metadata(os_name)
+        };
+        let metadata = match result {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                println!("{}: {}", String::from_utf8_lossy(name), Difference::Missing);
+                return Ok(true);
+            }
+            Err(err) => return Err(err),
+        };
+
+        let xattrs = list_xattrs(name, follow)?;
+        let differences = parse_line(line)?.compare(&metadata, &xattrs);
+        for difference in &differences {
+            println!("{}: {}", String::from_utf8_lossy(name), difference);
+        }
+        Ok(!differences.is_empty())
+    };
+
+    let mut result = ExitCode::SUCCESS;
+    let mut handle = |outcome: Result<bool, ErrorWithPath<io::Error>>| match outcome {
+        Ok(false) => {}
+        Ok(true) => result = ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("{}", err);
+            result = ExitCode::FAILURE;
+        }
+    };
+
+    match files {
+        None => {
+            for (name, line) in &stat_file {
+                handle(with_error_path(name.as_ref(), || {
+                    report(name.as_ref(), line.as_ref())
+                }));
+            }
+        }
+        Some(files) => {
+            for name in files {
+                handle(with_error_path(name, || {
+                    if let Some(line) = stat_file.get(name) {
+                        report(name, line)
+                    } else {
+                        Err(io::Error::new(
+                            ErrorKind::InvalidInput,
+                            "Not found in stat file",
+                        ))
+                    }
+                }));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, is_skipped, strip_dot_slash};
+
+    #[test]
+    fn glob_match_literal() {
+        assert!(glob_match(b"target", b"target"));
+        assert!(!glob_match(b"target", b"targ"));
+        assert!(!glob_match(b"target", b"target/x"));
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match(b"*.rs", b"main.rs"));
+        assert!(glob_match(b"target*", b"target"));
+        // `*` spans `/`, so a top-level glob omits the whole subtree.
+        assert!(glob_match(b"target*", b"target/debug/app"));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(!glob_match(b"*.rs", b"main.txt"));
+    }
+
+    #[test]
+    fn strip_dot_slash_prefix() {
+        assert_eq!(strip_dot_slash(b"./sub"), b"sub".as_slice());
+        assert_eq!(strip_dot_slash(b"./sub/b.txt"), b"sub/b.txt".as_slice());
+        assert_eq!(strip_dot_slash(b"sub"), b"sub".as_slice());
+        assert_eq!(strip_dot_slash(b"."), b".".as_slice());
+        // Only a single leading `./` is removed.
+        assert_eq!(strip_dot_slash(b"././x"), b"./x".as_slice());
+    }
+
+    #[test]
+    fn skip_stat_file_and_excludes() {
+        let tmp = b".filestat.tmp";
+        let excludes: &[&[u8]] = &[b"sub*"];
+
+        // The walk spells the stat file under `.` as `./.filestat`.
+        assert!(is_skipped(b"./.filestat", tmp, excludes));
+        assert!(is_skipped(b"./.filestat.tmp", tmp, excludes));
+        assert!(is_skipped(b".filestat", tmp, excludes));
+        assert!(is_skipped(b"./sub/b.txt", tmp, excludes));
+        assert!(!is_skipped(b"./keep.txt", tmp, excludes));
+    }
+}
@@ -1,7 +1,11 @@
-use rustix::fs::{AtFlags, CWD, Mode, RawMode, Timespec, Timestamps, chmodat, utimensat};
+use rustix::fs::{
+    AtFlags, CWD, Gid, Mode, RawMode, Timespec, Timestamps, Uid, XattrFlags, chmodat, chownat,
+    getxattr, lgetxattr, listxattr, llistxattr, lsetxattr, setxattr, utimensat,
+};
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::error;
+use std::fmt;
 use std::fs::{File, Metadata, read, rename};
 use std::io::{BufWriter, Error, ErrorKind, Result, Write};
 use std::os::unix::fs::MetadataExt;
@@ -19,11 +23,26 @@ pub fn read_stat_file(filename: &str, create: bool) -> Result<Vec<u8>> {
     }
 }
 
+/// Magic header identifying the compact binary format. It starts with a NUL
+/// byte, which the text format never contains (control bytes are escaped), so
+/// the two encodings can be told apart unambiguously on read.
+const BINARY_MAGIC: &[u8] = b"\x00fsr1";
+
+const TAG_MODE: u64 = 1;
+const TAG_MTIME: u64 = 2;
+const TAG_UID: u64 = 3;
+const TAG_GID: u64 = 4;
+const TAG_XATTR: u64 = 5;
+
 pub fn parse_stat_file(data: &[u8]) -> Result<StatFile<'_>> {
-    data.split(|&b| b == b'\n')
-        .filter(|s| !s.is_empty())
-        .map(extract_name)
-        .collect()
+    if let Some(body) = data.strip_prefix(BINARY_MAGIC) {
+        parse_stat_file_binary(body)
+    } else {
+        data.split(|&b| b == b'\n')
+            .filter(|s| !s.is_empty())
+            .map(extract_name)
+            .collect()
+    }
 }
 
 pub fn write_stat_file(filename: &str, data: &StatFile) -> Result<()> {
@@ -39,20 +58,256 @@ pub fn write_stat_file(filename: &str, data: &StatFile) -> Result<()> {
     rename(tmp, filename)
 }
 
-pub fn make_line(name: &[u8], metadata: &Metadata) -> Vec<u8> {
+pub fn write_stat_file_binary(filename: &str, data: &StatFile) -> Result<()> {
+    let buf = encode_stat_file(data)?;
+
+    let tmp = filename.to_owned() + ".tmp";
+    {
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        file.write_all(&buf)?;
+        file.into_inner()?.sync_all()?;
+    }
+    rename(tmp, filename)
+}
+
+fn encode_stat_file(data: &StatFile) -> Result<Vec<u8>> {
+    let mut buf = BINARY_MAGIC.to_vec();
+    for (name, line) in data {
+        encode_record(&mut buf, name, &parse_line(line)?);
+    }
+    Ok(buf)
+}
+
+fn parse_stat_file_binary(data: &[u8]) -> Result<StatFile<'_>> {
+    let mut pos = 0;
+    let mut map = StatFile::new();
+    while pos < data.len() {
+        let name_len = read_varint(data, &mut pos)?;
+        let name = read_bytes(data, &mut pos, name_len)?;
+        let field_count = read_varint(data, &mut pos)?;
+
+        let (mut mode, mut mtime, mut uid, mut gid) = (None, None, None, None);
+        let mut xattrs = Vec::new();
+        for _ in 0..field_count {
+            let tag = read_varint(data, &mut pos)?;
+            let len = read_varint(data, &mut pos)?;
+            let value = read_bytes(data, &mut pos, len)?;
+            let mut vpos = 0;
+            match tag {
+                TAG_MODE => mode = Some(read_u32(value, &mut vpos)?),
+                TAG_MTIME => {
+                    let sec = read_i64(value, &mut vpos)?;
+                    let nsec = read_i64(value, &mut vpos)?;
+                    mtime = Some((sec, nsec));
+                }
+                TAG_UID => uid = Some(read_u32(value, &mut vpos)?),
+                TAG_GID => gid = Some(read_u32(value, &mut vpos)?),
+                TAG_XATTR => {
+                    let attr_len = read_varint(value, &mut vpos)?;
+                    let attr = read_bytes(value, &mut vpos, attr_len)?;
+                    xattrs.push((attr.to_vec(), value[vpos..].to_vec()));
+                }
+                _ => {}
+            }
+        }
+
+        let line = build_text_line(name, mode, mtime, uid, gid, &xattrs);
+        map.insert(name.into(), line.into());
+    }
+    Ok(map)
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn encode_record(buf: &mut Vec<u8>, name: &[u8], apply: &StatApply) {
+    write_varint(buf, name.len() as u64);
+    buf.extend_from_slice(name);
+
+    let mut fields: Vec<(u64, Vec<u8>)> = Vec::new();
+    if let Some(mode) = apply.mode {
+        let mut value = Vec::new();
+        write_varint(&mut value, u64::from(mode));
+        fields.push((TAG_MODE, value));
+    }
+    if let Some(mtime) = apply.mtime {
+        let mut value = Vec::new();
+        write_varint(&mut value, mtime.tv_sec as u64);
+        write_varint(&mut value, mtime.tv_nsec as u64);
+        fields.push((TAG_MTIME, value));
+    }
+    if let Some(uid) = apply.uid {
+        let mut value = Vec::new();
+        write_varint(&mut value, u64::from(uid.as_raw()));
+        fields.push((TAG_UID, value));
+    }
+    if let Some(gid) = apply.gid {
+        let mut value = Vec::new();
+        write_varint(&mut value, u64::from(gid.as_raw()));
+        fields.push((TAG_GID, value));
+    }
+    for (attr, attr_value) in &apply.xattrs {
+        let mut value = Vec::new();
+        write_varint(&mut value, attr.len() as u64);
+        value.extend_from_slice(attr);
+        value.extend_from_slice(attr_value);
+        fields.push((TAG_XATTR, value));
+    }
+
+    write_varint(buf, fields.len() as u64);
+    for (tag, value) in fields {
+        write_varint(buf, tag);
+        write_varint(buf, value.len() as u64);
+        buf.extend_from_slice(&value);
+    }
+}
+
+/// Rebuild the canonical text line from decoded binary fields, so the rest of
+/// the crate keeps working with a single in-memory representation.
+fn build_text_line(
+    name: &[u8],
+    mode: Option<RawMode>,
+    mtime: Option<(i64, i64)>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    xattrs: &[(Vec<u8>, Vec<u8>)],
+) -> Vec<u8> {
     let mut line = escape(name).into_owned();
-    line.append(
-        &mut format!(
-            "\tmode={:03o}\tmtime={}.{:09}",
-            metadata.mode(),
-            metadata.mtime(),
-            metadata.mtime_nsec(),
-        )
-        .into_bytes(),
-    );
+    if let Some(mode) = mode {
+        line.append(&mut format!("\tmode={mode:03o}").into_bytes());
+    }
+    if let Some((sec, nsec)) = mtime {
+        line.append(&mut format!("\tmtime={sec}.{nsec:09}").into_bytes());
+    }
+    if let Some(uid) = uid {
+        line.append(&mut format!("\tuid={uid}").into_bytes());
+    }
+    if let Some(gid) = gid {
+        line.append(&mut format!("\tgid={gid}").into_bytes());
+    }
+    for (attr, value) in xattrs {
+        line.extend_from_slice(b"\txattr=");
+        line.extend_from_slice(&escape(attr));
+        line.push(b':');
+        line.append(&mut hex_encode(value));
+    }
+    line
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos).ok_or_else(|| invalid_data("truncated varint"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(invalid_data("varint too long"));
+        }
+    }
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: u64) -> Result<&'a [u8]> {
+    #[allow(clippy::cast_possible_truncation)]
+    let len = len as usize;
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| invalid_data("truncated record"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn read_u32(data: &[u8], pos: &mut usize) -> Result<u32> {
+    Ok(read_varint(data, pos)? as u32)
+}
+
+#[allow(clippy::cast_possible_wrap)]
+fn read_i64(data: &[u8], pos: &mut usize) -> Result<i64> {
+    Ok(read_varint(data, pos)? as i64)
+}
+
+pub fn make_line(
+    name: &[u8],
+    metadata: &Metadata,
+    owner: bool,
+    mtime: bool,
+    xattrs: &[(Vec<u8>, Vec<u8>)],
+) -> Vec<u8> {
+    let mut line = escape(name).into_owned();
+    line.append(&mut format!("\tmode={:03o}", metadata.mode()).into_bytes());
+    if mtime {
+        line.append(
+            &mut format!("\tmtime={}.{:09}", metadata.mtime(), metadata.mtime_nsec()).into_bytes(),
+        );
+    }
+    if owner {
+        line.append(&mut format!("\tuid={}\tgid={}", metadata.uid(), metadata.gid()).into_bytes());
+    }
+    for (name, value) in xattrs {
+        line.extend_from_slice(b"\txattr=");
+        line.extend_from_slice(&escape(name));
+        line.push(b':');
+        line.append(&mut hex_encode(value));
+    }
     line
 }
 
+/// List the extended attributes of `name` as sorted `(name, value)` pairs.
+///
+/// The `follow` flag selects between the symlink-following and no-following
+/// variants so it matches the behaviour of the rest of the recording pass.
+pub fn list_xattrs(name: &[u8], follow: bool) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let list = if follow {
+        let size = listxattr(name, &mut [])?;
+        let mut buf = vec![0u8; size];
+        let n = listxattr(name, &mut buf)?;
+        buf.truncate(n);
+        buf
+    } else {
+        let size = llistxattr(name, &mut [])?;
+        let mut buf = vec![0u8; size];
+        let n = llistxattr(name, &mut buf)?;
+        buf.truncate(n);
+        buf
+    };
+
+    let mut attrs = Vec::new();
+    for attr in list.split(|&b| b == 0).filter(|s| !s.is_empty()) {
+        let value = if follow {
+            let size = getxattr(name, attr, &mut [])?;
+            let mut buf = vec![0u8; size];
+            let n = getxattr(name, attr, &mut buf)?;
+            buf.truncate(n);
+            buf
+        } else {
+            let size = lgetxattr(name, attr, &mut [])?;
+            let mut buf = vec![0u8; size];
+            let n = lgetxattr(name, attr, &mut buf)?;
+            buf.truncate(n);
+            buf
+        };
+        attrs.push((attr.to_vec(), value));
+    }
+    attrs.sort();
+    Ok(attrs)
+}
+
 fn extract_name(line: &[u8]) -> Result<StatFileEntry<'_>> {
     let name = line.split(|&b| b == b'\t').next().unwrap();
     Ok((unescape(name)?, line.into()))
@@ -68,6 +323,9 @@ pub fn parse_line(line: &[u8]) -> Result<StatApply> {
         ) {
             (b"mode=", data) => apply.set_mode(data)?,
             (b"mtime=", data) => apply.set_mtime(data)?,
+            (b"uid=", data) => apply.set_uid(data)?,
+            (b"gid=", data) => apply.set_gid(data)?,
+            (b"xattr=", data) => apply.add_xattr(data)?,
             _ => {}
         }
     }
@@ -78,6 +336,9 @@ pub fn parse_line(line: &[u8]) -> Result<StatApply> {
 pub struct StatApply {
     mode: Option<RawMode>,
     mtime: Option<Timespec>,
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
 impl StatApply {
@@ -91,6 +352,38 @@ impl StatApply {
         Ok(())
     }
 
+    pub fn set_uid(&mut self, data: &[u8]) -> Result<()> {
+        let data = str::from_utf8(data).map_err(invalid_data)?;
+        let raw = str::parse(data).map_err(invalid_data)?;
+        // SAFETY: any parsed u32 is a valid raw uid; `chownat` rejects a
+        // nonexistent id at the kernel with the usual per-file error.
+        self.uid = Some(unsafe { Uid::from_raw(raw) });
+        Ok(())
+    }
+
+    pub fn set_gid(&mut self, data: &[u8]) -> Result<()> {
+        let data = str::from_utf8(data).map_err(invalid_data)?;
+        let raw = str::parse(data).map_err(invalid_data)?;
+        // SAFETY: any parsed u32 is a valid raw gid; `chownat` rejects a
+        // nonexistent id at the kernel with the usual per-file error.
+        self.gid = Some(unsafe { Gid::from_raw(raw) });
+        Ok(())
+    }
+
+    pub fn add_xattr(&mut self, data: &[u8]) -> Result<()> {
+        // Split on the *last* colon: the hex-encoded value never contains one,
+        // while an attribute name can (e.g. a `:`-bearing `user.*` key), so the
+        // final colon is always the true name/value boundary.
+        let sep = data
+            .iter()
+            .rposition(|&b| b == b':')
+            .ok_or_else(|| invalid_data("missing ':' in xattr field"))?;
+        let name = unescape(&data[..sep])?.into_owned();
+        let value = hex_decode(&data[sep + 1..])?;
+        self.xattrs.push((name, value));
+        Ok(())
+    }
+
     #[allow(clippy::similar_names)]
     pub fn set_mtime(&mut self, data: &[u8]) -> Result<()> {
         let data = str::from_utf8(data).map_err(invalid_data)?;
@@ -116,7 +409,7 @@ impl StatApply {
             .is_some_and(|mode| (mode & 0o170_000) == 0o120_000)
     }
 
-    pub fn apply(&self, name: &[u8], follow: bool) -> Result<()> {
+    pub fn apply(&self, name: &[u8], follow: bool, owner: bool) -> Result<()> {
         if name
             .split(|&b| b == b'/')
             .any(|c| c.is_empty() || c == b"..")
@@ -131,6 +424,10 @@ impl StatApply {
             AtFlags::SYMLINK_NOFOLLOW
         };
 
+        if owner && (self.uid.is_some() || self.gid.is_some()) {
+            chownat(CWD, name, self.uid, self.gid, flags)?;
+        }
+
         if let Some(mode) = self.mode {
             if follow {
                 chmodat(CWD, name, Mode::from_bits_truncate(mode & 0o777), flags)?;
@@ -145,12 +442,129 @@ impl StatApply {
             utimensat(CWD, name, &times, flags)?;
         }
 
+        for (attr, value) in &self.xattrs {
+            if follow {
+                setxattr(name, attr, value, XattrFlags::empty())?;
+            } else {
+                lsetxattr(name, attr, value, XattrFlags::empty())?;
+            }
+        }
+
         Ok(())
     }
+
+    /// Compare the recorded state against the current `metadata` and `xattrs`,
+    /// returning one [`Difference`] per field that no longer matches. Only the
+    /// fields that were recorded are examined, mirroring the set restored by
+    /// [`apply`]. `xattrs` is the current attribute set as produced by
+    /// [`list_xattrs`] with the same follow semantics used for the stat.
+    ///
+    /// [`apply`]: StatApply::apply
+    #[allow(clippy::cast_sign_loss)]
+    pub fn compare(&self, metadata: &Metadata, xattrs: &[(Vec<u8>, Vec<u8>)]) -> Vec<Difference> {
+        let mut differences = Vec::new();
+
+        if let Some(mode) = self.mode {
+            if mode != metadata.mode() {
+                differences.push(Difference::Mode {
+                    recorded: mode,
+                    current: metadata.mode(),
+                });
+            }
+        }
+
+        if let Some(mtime) = self.mtime {
+            if mtime.tv_sec != metadata.mtime() || mtime.tv_nsec != metadata.mtime_nsec() {
+                differences.push(Difference::Mtime);
+            }
+        }
+
+        if let Some(uid) = self.uid {
+            if uid.as_raw() != metadata.uid() {
+                differences.push(Difference::Uid {
+                    recorded: uid.as_raw(),
+                    current: metadata.uid(),
+                });
+            }
+        }
+
+        if let Some(gid) = self.gid {
+            if gid.as_raw() != metadata.gid() {
+                differences.push(Difference::Gid {
+                    recorded: gid.as_raw(),
+                    current: metadata.gid(),
+                });
+            }
+        }
+
+        for (attr, value) in &self.xattrs {
+            if xattrs.iter().find(|(a, _)| a == attr).map(|(_, v)| v) != Some(value) {
+                differences.push(Difference::Xattr {
+                    name: attr.clone(),
+                });
+            }
+        }
+
+        differences
+    }
+}
+
+/// A single recorded field that no longer matches the filesystem, as produced
+/// by [`StatApply::compare`] and surfaced by the `check` subcommand.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Difference {
+    Missing,
+    Mode { recorded: RawMode, current: RawMode },
+    Mtime,
+    Uid { recorded: u32, current: u32 },
+    Gid { recorded: u32, current: u32 },
+    Xattr { name: Vec<u8> },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::Missing => write!(f, "missing"),
+            Difference::Mode { recorded, current } => {
+                write!(f, "mode {recorded:o} != {current:o}")
+            }
+            Difference::Mtime => write!(f, "mtime drift"),
+            Difference::Uid { recorded, current } => write!(f, "uid {recorded} != {current}"),
+            Difference::Gid { recorded, current } => write!(f, "gid {recorded} != {current}"),
+            Difference::Xattr { name } => {
+                write!(f, "xattr {} differs", String::from_utf8_lossy(name))
+            }
+        }
+    }
 }
 
 const HEXDIGIT: &[u8] = b"0123456789abcdef";
 
+fn hex_encode(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() * 2);
+    for &c in data {
+        buf.push(HEXDIGIT[(c / 16) as usize]);
+        buf.push(HEXDIGIT[(c % 16) as usize]);
+    }
+    buf
+}
+
+fn hex_decode(data: &[u8]) -> Result<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return Err(invalid_data("odd-length hexadecimal value"));
+    }
+    data.chunks_exact(2)
+        .map(|pair| {
+            let (hi, lo) = (char::from(pair[0]), char::from(pair[1]));
+            match (hi.to_digit(16), lo.to_digit(16)) {
+                #[allow(clippy::cast_possible_truncation)]
+                (Some(hi), Some(lo)) => Ok((hi * 16 + lo) as u8),
+                _ => Err(invalid_data(format!("invalid hexadecimal value \\x{hi}{lo}"))),
+            }
+        })
+        .collect()
+}
+
 fn escape(name: &[u8]) -> Cow<'_, [u8]> {
     let escape_high = str::from_utf8(name).is_err();
     let escape_byte = |c: u8| c.is_ascii_control() || c == b'\\' || escape_high && c >= 0x80;
@@ -302,7 +716,10 @@ mod tests {
             parse_line(b"name").unwrap(),
             StatApply {
                 mode: None,
-                mtime: None
+                mtime: None,
+                uid: None,
+                gid: None,
+                xattrs: Vec::new()
             }
         );
         assert_eq!(
@@ -312,7 +729,10 @@ mod tests {
                 mtime: Some(Timespec {
                     tv_sec: 4321,
                     tv_nsec: 123456789
-                })
+                }),
+                uid: None,
+                gid: None,
+                xattrs: Vec::new()
             }
         );
         assert_eq!(
@@ -322,11 +742,36 @@ mod tests {
                 mtime: Some(Timespec {
                     tv_sec: 4321,
                     tv_nsec: 123456789
-                })
+                }),
+                uid: None,
+                gid: None,
+                xattrs: Vec::new()
+            }
+        );
+        assert_eq!(
+            parse_line(b"name\tmode=100644\tmtime=4321.123456789\tuid=1000\tgid=100").unwrap(),
+            StatApply {
+                mode: Some(0o100644),
+                mtime: Some(Timespec {
+                    tv_sec: 4321,
+                    tv_nsec: 123456789
+                }),
+                // SAFETY: 1000/100 are valid raw ids for this comparison.
+                uid: Some(unsafe { super::Uid::from_raw(1000) }),
+                gid: Some(unsafe { super::Gid::from_raw(100) }),
+                xattrs: Vec::new()
             }
         );
     }
 
+    #[test]
+    fn xattr_name_with_colon() {
+        use super::parse_line;
+
+        let apply = parse_line(b"name\txattr=user:weird:0102").unwrap();
+        assert_eq!(apply.xattrs, vec![(b"user:weird".to_vec(), vec![1u8, 2u8])]);
+    }
+
     #[test]
     fn invalid_path() {
         test_invalid_path(b"/root", false);
@@ -346,7 +791,41 @@ mod tests {
         use super::StatApply;
         use std::io::ErrorKind;
 
-        let error = StatApply::new().apply(name, follow).unwrap_err();
+        let error = StatApply::new().apply(name, follow, true).unwrap_err();
         assert_eq!(error.kind(), ErrorKind::InvalidData);
     }
+
+    #[test]
+    fn binary_roundtrip() {
+        use super::{encode_stat_file, parse_stat_file, BINARY_MAGIC};
+
+        // First entry's name embeds a tab and a newline (escaped in the text
+        // form) and carries uid/gid plus an xattr; the binary form stores the
+        // name raw, so the round-trip must reproduce the map exactly.
+        let text = b"a\\x09b\\x0ac\tmode=100644\tmtime=4321.123456789\tuid=1000\tgid=100\txattr=user.x:00ff\n\
+                     plain\tmode=100600\tmtime=1.000000000\n";
+        let map = parse_stat_file(text).unwrap();
+
+        let binary = encode_stat_file(&map).unwrap();
+        assert!(binary.starts_with(BINARY_MAGIC));
+
+        let decoded = parse_stat_file(&binary).unwrap();
+        assert_eq!(map, decoded);
+    }
+
+    #[test]
+    fn binary_truncated() {
+        use super::{parse_stat_file, BINARY_MAGIC};
+
+        // Record claims a 10-byte name but supplies only three.
+        let mut short_name = BINARY_MAGIC.to_vec();
+        short_name.push(10);
+        short_name.extend_from_slice(b"abc");
+        assert!(parse_stat_file(&short_name).is_err());
+
+        // Varint with the continuation bit set but no following byte.
+        let mut short_varint = BINARY_MAGIC.to_vec();
+        short_varint.push(0x80);
+        assert!(parse_stat_file(&short_varint).is_err());
+    }
 }